@@ -1,21 +1,163 @@
-use failure::{bail, Error};
-use futures::{future::FutureResult, Future};
+use failure::{bail, format_err, Error};
+use futures::{future::FutureResult, Future, Stream};
 use log::{debug, error, info};
+use net2::TcpBuilder;
 use quicli::prelude::Verbosity;
 use quinn::Endpoint;
+use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 use tokio::{
     io::{self, AsyncRead, AsyncWrite},
+    net::TcpStream,
+    reactor::Handle,
     runtime::current_thread::Runtime,
+    timer::Delay,
 };
-use trust_dns_resolver::AsyncResolver;
+use tokio_rustls::TlsConnector;
+
+mod resolver;
+use resolver::Resolve;
 
 #[derive(Debug, StructOpt)]
 enum Protocol {
     /// The QUIC protocol, from google, built to improve upon TCP. Underlying protocol of HTTP/3.
     #[structopt(name = "quic")]
     Quic(QuicOptions),
+    /// Plain, unencrypted TCP. What classic netcat speaks.
+    #[structopt(name = "tcp")]
+    Tcp,
+    /// TCP wrapped in a TLS session.
+    #[structopt(name = "tls")]
+    Tls(TlsOptions),
+}
+
+impl Protocol {
+    /// Connect using whichever transport was selected, erasing the concrete stream type so
+    /// callers don't need to match on every variant.
+    pub fn connect<'a>(
+        &'a self,
+        address: SocketAddr,
+        hostname: &'a str,
+        source: Option<SocketAddr>,
+    ) -> Box<dyn 'a + Future<Item = (Box<dyn AsyncRead>, Box<dyn AsyncWrite>), Error = Error>> {
+        fn erase<'a>(
+            future: impl 'a + Future<Item = (impl 'a + AsyncRead, impl 'a + AsyncWrite), Error = Error>,
+        ) -> Box<dyn 'a + Future<Item = (Box<dyn AsyncRead>, Box<dyn AsyncWrite>), Error = Error>>
+        {
+            Box::new(future.map(|(recv, send)| {
+                (Box::new(recv) as Box<dyn AsyncRead>, Box::new(send) as Box<dyn AsyncWrite>)
+            }))
+        }
+
+        match self {
+            Protocol::Quic(options) => erase(options.connect(address, hostname, source)),
+            Protocol::Tcp => erase(connect_tcp(address, source)),
+            Protocol::Tls(options) => erase(options.connect(address, hostname, source)),
+        }
+    }
+}
+
+/// Build a std TCP socket bound to `source` (if given) and ready to connect to `address`, so the
+/// outbound connection originates from the requested local interface.
+fn bind_tcp(address: SocketAddr, source: Option<SocketAddr>) -> Result<std::net::TcpStream, Error> {
+    let builder = match address {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+
+    if let Some(source) = source {
+        builder.bind(source)?;
+    }
+
+    Ok(builder.to_tcp_stream()?)
+}
+
+/// Connect a plain `tokio::net::TcpStream`, optionally bound to a local `source` address. Shared
+/// by the raw TCP transport and by TLS-over-TCP, which needs the unsplit stream to wrap in TLS.
+fn dial_tcp<'a>(
+    address: SocketAddr,
+    source: Option<SocketAddr>,
+) -> impl 'a + Future<Item = TcpStream, Error = Error> {
+    futures::future::result(bind_tcp(address, source)).and_then(move |socket| {
+        TcpStream::connect_std(socket, &address, &Handle::default()).map_err(Error::from)
+    })
+}
+
+/// Connect over plain TCP, with no encryption. The simplest transport, and the one the
+/// overwhelming majority of existing netcat servers speak.
+fn connect_tcp<'a>(
+    address: SocketAddr,
+    source: Option<SocketAddr>,
+) -> impl 'a + Future<Item = (impl AsyncRead, impl AsyncWrite), Error = Error> {
+    dial_tcp(address, source)
+        .map(io::split)
+        .inspect(|_| info!("Connection established"))
+}
+
+#[derive(Debug, StructOpt)]
+struct TlsOptions {
+    /// The hostname used to validate the server's TLS certificate. Will default to the provided
+    /// hostname.
+    #[structopt(long = "dns-name")]
+    dns_name: Option<String>,
+}
+
+impl TlsOptions {
+    /// Connect over TCP, then negotiate a TLS session on top, trusting the Mozilla root
+    /// certificates bundled by `webpki-roots` (not the operating system's trust store).
+    pub fn connect<'a>(
+        &'a self,
+        address: SocketAddr,
+        hostname: &'a str,
+        source: Option<SocketAddr>,
+    ) -> impl 'a + Future<Item = (impl AsyncRead, impl AsyncWrite), Error = Error> {
+        let server_name = self
+            .dns_name
+            .as_ref()
+            .map(String::as_str)
+            .unwrap_or(hostname);
+
+        let domain = webpki::DNSNameRef::try_from_ascii_str(server_name)
+            .map(|domain| domain.to_owned())
+            .map_err(|_| format_err!("Invalid DNS name {}", server_name));
+        let domain = futures::future::result(domain);
+
+        let mut root_store = rustls::RootCertStore::empty();
+        root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        let mut config = rustls::ClientConfig::new();
+        config.root_store = root_store;
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let tcp = dial_tcp(address, source);
+
+        domain
+            .join(tcp)
+            .and_then(move |(domain, tcp)| {
+                connector.connect(domain.as_ref(), tcp).map_err(Error::from)
+            })
+            .map(io::split)
+            .inspect(|_| info!("Connection established"))
+    }
+}
+
+/// A `rustls::ServerCertVerifier` that accepts any certificate. Installed by `--insecure` for
+/// testing against self-signed servers without needing to supply a CA.
+struct InsecureCertificateVerifier;
+
+impl rustls::ServerCertVerifier for InsecureCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -24,23 +166,91 @@ struct QuicOptions {
     /// hostname.
     #[structopt(long = "dns-name")]
     dns_name: Option<String>,
+
+    /// Path to a PEM encoded certificate to present when listening. Must be paired with
+    /// `--key`. If neither is given, a self-signed certificate is generated for the session.
+    #[structopt(long = "cert", parse(from_os_str))]
+    cert: Option<PathBuf>,
+
+    /// Path to the PEM encoded private key matching `--cert`.
+    #[structopt(long = "key", parse(from_os_str))]
+    key: Option<PathBuf>,
+
+    /// Path to a PEM encoded client certificate to present for mutual TLS. Must be paired with
+    /// `--client-key`.
+    #[structopt(long = "client-cert", parse(from_os_str))]
+    client_cert: Option<PathBuf>,
+
+    /// Path to the PEM encoded private key matching `--client-cert`.
+    #[structopt(long = "client-key", parse(from_os_str))]
+    client_key: Option<PathBuf>,
+
+    /// Path to a PEM file of additional root certificates to trust, for validating a server that
+    /// uses a private CA.
+    #[structopt(long = "ca", parse(from_os_str))]
+    ca: Option<PathBuf>,
+
+    /// Skip validation of the server's certificate entirely. Only for testing against
+    /// self-signed servers.
+    #[structopt(long = "insecure")]
+    insecure: bool,
 }
 
 impl QuicOptions {
+    /// Build the client config used to connect out: the default root store, plus any `--ca`
+    /// given, a client certificate if `--client-cert`/`--client-key` were given, and the
+    /// `--insecure` escape hatch.
+    fn client_config(&self) -> Result<quinn::ClientConfig, Error> {
+        let mut builder = quinn::ClientConfigBuilder::default();
+
+        if let Some(ca_path) = &self.ca {
+            let ca = quinn::Certificate::from_pem(&fs::read(ca_path)?)?;
+            builder.add_certificate_authority(ca)?;
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert_chain = quinn::CertificateChain::from_pem(&fs::read(cert_path)?)?;
+                let key = quinn::PrivateKey::from_pem(&fs::read(key_path)?)?;
+                builder.set_client_cert(cert_chain, key)?;
+            }
+            (None, None) => {}
+            _ => bail!("--client-cert and --client-key must be given together"),
+        }
+
+        let mut config = builder.build();
+
+        if self.insecure {
+            let tls_config = Arc::get_mut(&mut config.crypto)
+                .expect("freshly built client config is uniquely owned");
+            tls_config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(InsecureCertificateVerifier));
+        }
+
+        Ok(config)
+    }
+
     /// Create a connection to the remote address. In case an explicit dns_name was not provided,
     /// the initial hostname is required for TLS verification of the domain.
     pub fn connect<'a>(
         &'a self,
         address: SocketAddr,
         hostname: &'a str,
+        source: Option<SocketAddr>,
     ) -> impl 'a + Future<Item = (impl AsyncRead, impl AsyncWrite), Error = Error> {
-        let endpoint = Endpoint::builder().bind("[::]:0").map_err(Error::from).map(
-            |(driver, endpoint, _incomming)| {
+        let bind_address = source.unwrap_or_else(|| "[::]:0".parse().unwrap());
+
+        let endpoint = futures::future::result(self.client_config())
+            .and_then(move |client_config| {
+                let mut builder = Endpoint::builder();
+                builder.default_client_config(client_config);
+                builder.bind(&bind_address).map_err(Error::from)
+            })
+            .map(|(driver, endpoint, _incomming)| {
                 tokio::spawn(driver.map_err(|err| error!("Endpoint error {}", err)));
                 endpoint
-            },
-        );
-        let endpoint = FutureResult::from(endpoint);
+            });
 
         let server_name = self
             .dns_name
@@ -63,14 +273,116 @@ impl QuicOptions {
 
         output.inspect(|_| info!("Connection established"))
     }
+
+    /// Load the certificate and private key to present to clients, generating a self-signed pair
+    /// if neither `--cert` nor `--key` was given.
+    fn identity(&self) -> Result<(quinn::CertificateChain, quinn::PrivateKey), Error> {
+        match (&self.cert, &self.key) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert_chain = quinn::CertificateChain::from_pem(&fs::read(cert_path)?)?;
+                let key = quinn::PrivateKey::from_pem(&fs::read(key_path)?)?;
+                Ok((cert_chain, key))
+            }
+            (None, None) => {
+                info!("No certificate provided, generating a self-signed one");
+                let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+                let key = quinn::PrivateKey::from_der(&cert.serialize_private_key_der())?;
+                let cert_chain = quinn::CertificateChain::from_certs(vec![
+                    quinn::Certificate::from_der(&cert.serialize_der()?)?,
+                ]);
+                Ok((cert_chain, key))
+            }
+            _ => bail!("--cert and --key must be given together"),
+        }
+    }
+
+    /// Listen for an inbound connection on the given port, accept the first bidirectional stream
+    /// it opens, and hand it off the same way `connect` does.
+    pub fn listen<'a>(
+        &'a self,
+        port: u16,
+        source: Option<SocketAddr>,
+    ) -> impl 'a + Future<Item = (impl AsyncRead, impl AsyncWrite), Error = Error> {
+        let endpoint = futures::future::result(self.identity()).and_then(move |(cert_chain, key)| {
+            let mut server_config = quinn::ServerConfigBuilder::default();
+            server_config.certificate(cert_chain, key)?;
+
+            let mut endpoint = Endpoint::builder();
+            endpoint.listen(server_config.build());
+
+            let address: SocketAddr = match source {
+                Some(mut source) => {
+                    source.set_port(port);
+                    source
+                }
+                None => format!("[::]:{}", port).parse()?,
+            };
+            Ok(endpoint.bind(&address).map_err(Error::from)?)
+        });
+
+        let incoming = FutureResult::from(endpoint).and_then(|(driver, _endpoint, incoming)| {
+            tokio::spawn(driver.map_err(|err| error!("Endpoint error {}", err)));
+
+            incoming
+                .into_future()
+                .map_err(|(err, _)| Error::from(err))
+                .and_then(|(connecting, _rest)| {
+                    connecting.ok_or_else(|| format_err!("No connection received"))
+                })
+        });
+
+        // `incoming_streams` is the only channel the client's bidirectional stream arrives
+        // through, so it has to be read directly here rather than drained in the background:
+        // `Connection::accept_bi` pulls from that same queue, and the two would race over which
+        // of them sees the client's first stream.
+        let streams = incoming
+            .and_then(|connecting| connecting.map_err(Error::from))
+            .map(|(driver, _connection, incoming_streams)| {
+                tokio::spawn(driver.map_err(|err| error!("Connection error {}", err)));
+                incoming_streams
+            });
+
+        let output = streams
+            .and_then(|incoming_streams| {
+                incoming_streams
+                    .filter_map(|stream| match stream {
+                        quinn::NewStream::Bi(send, recv) => Some((send, recv)),
+                        quinn::NewStream::Uni(_) => None,
+                    })
+                    .into_future()
+                    .map_err(|(err, _rest)| Error::from(err))
+                    .and_then(|(stream, _rest)| {
+                        stream.ok_or_else(|| format_err!("No bidirectional stream received"))
+                    })
+            })
+            .map(|(send, recv)| (recv, send));
+
+        output.inspect(|_| info!("Connection accepted"))
+    }
 }
 
 #[derive(Debug, StructOpt)]
 struct Options {
-    /// The hostname or IP address to connect to.
+    /// The hostname or IP address to connect to. Ignored when `--listen` is given.
     hostname: String,
-    /// The port number to connect to
+    /// The port number to connect to, or to listen on when `--listen` is given.
     port: u16,
+    /// Listen for an inbound connection instead of connecting out, turning this into a server.
+    #[structopt(short = "l", long = "listen")]
+    listen: bool,
+    /// Delay, in milliseconds, between launching successive connection attempts when racing
+    /// multiple resolved addresses. 250ms is the value recommended by RFC 8305 ("Happy
+    /// Eyeballs").
+    #[structopt(long = "stagger", default_value = "250")]
+    stagger: u64,
+    /// Local address to originate outbound connections and DNS lookups from. Useful for pinning
+    /// traffic to a specific interface on a multi-homed host.
+    #[structopt(short = "s", long = "source")]
+    source: Option<SocketAddr>,
+    /// How to resolve `hostname`: "system" uses the host's usual stub resolver (the default),
+    /// "doq://host[:port]" bootstraps resolution over DNS-over-QUIC against the given upstream.
+    #[structopt(long = "resolver", default_value = "system")]
+    resolver: resolver::ResolverKind,
     #[structopt(flatten)]
     protocol: Protocol,
     #[structopt(flatten)]
@@ -85,36 +397,65 @@ impl Options {
         debug!("Runtime initialized");
         Ok(runtime)
     }
+}
 
-    /// Find the IP address of the remote host
-    pub fn get_address<'a>(&'a self) -> impl 'a + Future<Item = SocketAddr, Error = Error> {
-        let resolver = futures::future::ok(()).and_then(|_| {
-            AsyncResolver::from_system_conf().map(|(resolver, driver)| {
-                tokio::spawn(driver);
-                debug!("Created DNS resolver {:?}", resolver);
-                resolver
-            })
-        });
+/// A single candidate address's connection attempt, erased to a common item type so
+/// `race_connections` doesn't need to know it's dialing a `Protocol` specifically. Production
+/// code passes `&|address| protocol.connect(address, hostname, source)`; tests can pass a stub.
+type Connect<'a, T> = dyn 'a + Fn(SocketAddr) -> Box<dyn 'a + Future<Item = T, Error = Error>>;
 
-        let responses =
-            resolver.and_then(move |resolver| resolver.lookup_ip(self.hostname.as_str()));
-
-        let ip =
-            responses
-                .map_err(Error::from)
-                .and_then(|responses| match responses.iter().next() {
-                    Some(output) => Ok(output),
-                    None => bail!("No DNS record found"),
-                });
-
-        let output = ip.map(move |ip| {
-            let addr = (ip, self.port).into();
-            info!("Found address {}", addr);
-            addr
-        });
+/// Race two already-started connection attempts against each other. Whichever finishes a
+/// handshake first wins; if one fails, fall back to waiting on the other.
+fn race_attempts<'a, T: 'a>(
+    a: Box<dyn 'a + Future<Item = T, Error = Error>>,
+    b: Box<dyn 'a + Future<Item = T, Error = Error>>,
+) -> Box<dyn 'a + Future<Item = T, Error = Error>> {
+    Box::new(a.select(b).then(|result| match result {
+        Ok((connection, _other)) => futures::future::Either::A(futures::future::ok(connection)),
+        Err((err, other)) => {
+            debug!("{}, trying remaining candidate", err);
+            futures::future::Either::B(other)
+        }
+    }))
+}
 
-        output
-    }
+/// Attempt to connect to `address` via `connect`. A candidate that fails immediately lets the
+/// next one in `addresses` start right away, with no wait. If the attempt is still pending after
+/// `stagger`, a second attempt to the next candidate is started concurrently, and whichever
+/// finishes a handshake first wins. This is RFC 8305 "Happy Eyeballs".
+fn race_connections<'a, T: 'a>(
+    connect: &'a Connect<'a, T>,
+    mut addresses: std::vec::IntoIter<SocketAddr>,
+    stagger: Duration,
+) -> Box<dyn 'a + Future<Item = T, Error = Error>> {
+    let address = match addresses.next() {
+        Some(address) => address,
+        None => return Box::new(futures::future::err(format_err!("No address connected"))),
+    };
+
+    // Tag the attempt's error with the address it came from up front, since once it's racing
+    // against other candidates there's no longer a single address to blame a failure on.
+    let attempt = connect(address).map_err(move |err| format_err!("{}: {}", address, err));
+    let attempt: Box<dyn 'a + Future<Item = T, Error = Error>> = Box::new(attempt);
+    let timeout = Delay::new(Instant::now() + stagger).map_err(Error::from);
+
+    Box::new(attempt.select2(timeout).then(move |outcome| {
+        match outcome {
+            Ok(futures::future::Either::A((connection, _timeout))) => {
+                Box::new(futures::future::ok(connection))
+                    as Box<dyn 'a + Future<Item = T, Error = Error>>
+            }
+            Err(futures::future::Either::A((err, _timeout))) => {
+                debug!("{}, trying next candidate immediately", err);
+                race_connections(connect, addresses, stagger)
+            }
+            Ok(futures::future::Either::B((_, attempt)))
+            | Err(futures::future::Either::B((_, attempt))) => {
+                let next = race_connections(connect, addresses, stagger);
+                race_attempts(attempt, next)
+            }
+        }
+    }))
 }
 
 /// Once a streaming connection has been established with the remote, run it to completion.
@@ -140,17 +481,81 @@ fn main() -> Result<(), Error> {
     let options = Options::from_args();
     let mut runtime = options.setup()?;
 
-    let address = options.get_address();
+    if options.listen {
+        let quic_options = match &options.protocol {
+            Protocol::Quic(quic_options) => quic_options,
+            _ => bail!("--listen is currently only supported for the quic protocol"),
+        };
 
-    let conn = address.and_then(|addr| {
+        let conn = quic_options.listen(options.port, options.source);
+        let main = conn.and_then(|(recv, send)| run_stream_connection(recv, send));
+
+        return runtime.block_on(main);
+    }
+
+    let source = options.source;
+    let resolver = options.resolver.build(source);
+    let address = resolver.resolve(&options.hostname, options.port);
+    let stagger = Duration::from_millis(options.stagger);
+
+    let conn = address.and_then(|addrs| {
         let Options {
             protocol, hostname, ..
         } = &options;
-        let Protocol::Quic(quic_options) = protocol;
-        quic_options.connect(addr, hostname)
+        let connect: &Connect<'_, (Box<dyn AsyncRead>, Box<dyn AsyncWrite>)> =
+            &|address| protocol.connect(address, hostname, source);
+        race_connections(connect, addrs.into_iter(), stagger)
     });
 
     let main = conn.and_then(|(recv, send)| run_stream_connection(recv, send));
 
     runtime.block_on(main)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Locks in the "dial next candidate immediately on failure, race the next one concurrently
+    /// once `stagger` has elapsed" behavior: the first candidate fails instantly, the second
+    /// hangs forever, and the third succeeds only once it's dialed after the stagger delay.
+    #[test]
+    fn race_connections_retries_immediately_and_races_after_stagger() {
+        let failing = SocketAddr::from(([127, 0, 0, 1], 0));
+        let hanging = SocketAddr::from(([127, 0, 0, 2], 0));
+        let succeeding = SocketAddr::from(([127, 0, 0, 3], 0));
+        let addresses = vec![failing, hanging, succeeding];
+        let stagger = Duration::from_millis(20);
+
+        let dialed = Arc::new(AtomicUsize::new(0));
+        let dialed_handle = Arc::clone(&dialed);
+        let connect: &Connect<'_, u8> =
+            &move |address: SocketAddr| -> Box<dyn Future<Item = u8, Error = Error>> {
+                dialed_handle.fetch_add(1, Ordering::SeqCst);
+                if address == failing {
+                    Box::new(futures::future::err(format_err!("connection refused")))
+                } else if address == hanging {
+                    Box::new(futures::future::empty())
+                } else {
+                    Box::new(futures::future::ok(3))
+                }
+            };
+
+        let mut runtime = Runtime::new().unwrap();
+        let start = Instant::now();
+        let result = runtime.block_on(race_connections(connect, addresses.into_iter(), stagger));
+        let elapsed = start.elapsed();
+
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(dialed.load(Ordering::SeqCst), 3);
+        assert!(
+            elapsed >= stagger,
+            "the hanging candidate should have forced a wait for the stagger delay"
+        );
+        assert!(
+            elapsed < stagger * 3,
+            "the failed candidate shouldn't also have waited out the stagger delay"
+        );
+    }
+}
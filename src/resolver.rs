@@ -0,0 +1,425 @@
+//! Pluggable hostname resolution, so the rest of the crate doesn't need to know whether an
+//! address came from the system stub resolver or somewhere else entirely.
+
+use failure::{bail, format_err, Error};
+use futures::Future;
+use log::{debug, error, info};
+use quinn::Endpoint;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::io::{read_exact, write_all};
+use trust_dns_proto::{
+    op::{Message, MessageType, OpCode, Query},
+    rr::{Name, RData, RecordType},
+    serialize::binary::{BinDecodable, BinEncodable},
+};
+use trust_dns_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    system_conf::read_system_conf,
+    AsyncResolver,
+};
+
+/// Turns a hostname into a set of candidate addresses. Shaped like hyper's
+/// `Service<Name, Response = impl Iterator<Item = SocketAddr>>`: a single async lookup method,
+/// so a stub implementation can stand in for this in tests.
+pub trait Resolve {
+    fn resolve<'a>(
+        &'a self,
+        hostname: &'a str,
+        port: u16,
+    ) -> Box<dyn 'a + Future<Item = Vec<SocketAddr>, Error = Error>>;
+}
+
+/// Interleave IPv6 and IPv4 addresses, IPv6 first, so that racing connection attempts in the
+/// returned order approximates RFC 8305 "Happy Eyeballs".
+fn interleave(ips: Vec<IpAddr>) -> Vec<IpAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = ips.into_iter().partition(|ip| ip.is_ipv6());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut out = Vec::with_capacity(v6.len() + v4.len());
+    loop {
+        let a = v6.next();
+        let b = v4.next();
+        if a.is_none() && b.is_none() {
+            break;
+        }
+        out.extend(a);
+        out.extend(b);
+    }
+    out
+}
+
+fn to_addresses(ips: Vec<IpAddr>, port: u16) -> Vec<SocketAddr> {
+    interleave(ips)
+        .into_iter()
+        .map(|ip| {
+            let addr = (ip, port).into();
+            info!("Found address {}", addr);
+            addr
+        })
+        .collect()
+}
+
+/// Build the resolver configuration, pinning every configured name server to `source` (when
+/// given) so lookups originate from the chosen local interface.
+fn system_resolver_config(source: Option<SocketAddr>) -> Result<(ResolverConfig, ResolverOpts), Error> {
+    let (config, opts) = read_system_conf()?;
+
+    let config = match source {
+        Some(source) => {
+            let name_servers: Vec<_> = config
+                .name_servers()
+                .iter()
+                .cloned()
+                .map(|mut server| {
+                    server.bind_addr = Some(source);
+                    server
+                })
+                .collect();
+            ResolverConfig::from_parts(config.domain().cloned(), config.search().to_vec(), name_servers)
+        }
+        None => config,
+    };
+
+    Ok((config, opts))
+}
+
+/// Resolve `hostname` to its IP addresses using the host's system DNS configuration
+/// (`/etc/resolv.conf` and friends), via trust-dns. `source`, not `self`, carries the local
+/// interface, so this can be reused both by `SystemResolver` and to bootstrap `DoqResolver`.
+fn lookup_system<'a>(
+    hostname: &'a str,
+    source: Option<SocketAddr>,
+) -> impl 'a + Future<Item = Vec<IpAddr>, Error = Error> {
+    let resolver = futures::future::result(system_resolver_config(source)).and_then(|(config, opts)| {
+        AsyncResolver::new(config, opts)
+            .map(|(resolver, driver)| {
+                tokio::spawn(driver);
+                debug!("Created DNS resolver {:?}", resolver);
+                resolver
+            })
+            .map_err(Error::from)
+    });
+
+    let responses =
+        resolver.and_then(move |resolver| resolver.lookup_ip(hostname).map_err(Error::from));
+
+    responses.and_then(|responses| {
+        let ips: Vec<IpAddr> = responses.iter().collect();
+        if ips.is_empty() {
+            bail!("No DNS record found");
+        }
+        Ok(ips)
+    })
+}
+
+/// Resolve hostnames using the host's system DNS configuration. The default, and the resolver
+/// the rest of the crate used before `Resolve` existed.
+pub struct SystemResolver {
+    source: Option<SocketAddr>,
+}
+
+impl SystemResolver {
+    pub fn new(source: Option<SocketAddr>) -> Self {
+        SystemResolver { source }
+    }
+}
+
+impl Resolve for SystemResolver {
+    fn resolve<'a>(
+        &'a self,
+        hostname: &'a str,
+        port: u16,
+    ) -> Box<dyn 'a + Future<Item = Vec<SocketAddr>, Error = Error>> {
+        Box::new(lookup_system(hostname, self.source).map(move |ips| to_addresses(ips, port)))
+    }
+}
+
+/// Resolve hostnames via DNS-over-QUIC (DoQ) against a single upstream resolver, so name
+/// resolution can be bootstrapped over an encrypted transport instead of the host's plaintext
+/// stub resolver. The upstream resolver's own hostname is looked up via the system resolver.
+pub struct DoqResolver {
+    upstream_host: String,
+    upstream_port: u16,
+    source: Option<SocketAddr>,
+}
+
+impl DoqResolver {
+    pub fn new(upstream_host: String, upstream_port: u16, source: Option<SocketAddr>) -> Self {
+        DoqResolver {
+            upstream_host,
+            upstream_port,
+            source,
+        }
+    }
+
+    /// Build the raw DNS wire-format query for a single record type of `hostname`. Each record
+    /// type is sent as its own message, since most resolvers only answer the first question in a
+    /// message that asks more than one.
+    fn query(hostname: &str, record_type: RecordType) -> Result<Vec<u8>, Error> {
+        let name = Name::from_str(hostname)?;
+
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true);
+        message.add_query(Query::query(name, record_type));
+
+        message.to_bytes().map_err(Error::from)
+    }
+
+    /// Open a fresh bidirectional stream on `connection`, send a query for `record_type`, and
+    /// parse the resulting addresses out of the response.
+    fn resolve_one(
+        connection: quinn::Connection,
+        hostname: &str,
+        record_type: RecordType,
+    ) -> impl Future<Item = Vec<IpAddr>, Error = Error> {
+        let query = futures::future::result(Self::query(hostname, record_type));
+        let stream = connection.open_bi().map_err(Error::from);
+
+        let response = stream.join(query).and_then(|((send, recv), query)| {
+            let len = (query.len() as u16).to_be_bytes();
+
+            write_all(send, len)
+                .and_then(move |(send, _)| write_all(send, query))
+                .map_err(Error::from)
+                .and_then(move |(_send, _)| {
+                    read_exact(recv, [0u8; 2])
+                        .map_err(Error::from)
+                        .and_then(move |(recv, len)| {
+                            let len = u16::from_be_bytes(len) as usize;
+                            read_exact(recv, vec![0u8; len]).map_err(Error::from)
+                        })
+                })
+        });
+
+        response.and_then(|(_recv, response)| {
+            let message = Message::from_bytes(&response)?;
+
+            let ips = message
+                .answers()
+                .iter()
+                .filter_map(|record| match record.rdata() {
+                    RData::A(addr) => Some(IpAddr::V4(*addr)),
+                    RData::AAAA(addr) => Some(IpAddr::V6(*addr)),
+                    _ => None,
+                })
+                .collect();
+
+            Ok(ips)
+        })
+    }
+}
+
+impl Resolve for DoqResolver {
+    fn resolve<'a>(
+        &'a self,
+        hostname: &'a str,
+        port: u16,
+    ) -> Box<dyn 'a + Future<Item = Vec<SocketAddr>, Error = Error>> {
+        let upstream_host = self.upstream_host.clone();
+        let source = self.source;
+
+        let upstream = lookup_system(&self.upstream_host, source).map(move |ips| {
+            ips.into_iter()
+                .map(move |ip| SocketAddr::from((ip, self.upstream_port)))
+                .collect::<Vec<_>>()
+        });
+
+        let bind_address = source.unwrap_or_else(|| "[::]:0".parse().unwrap());
+        let mut client_config = quinn::ClientConfigBuilder::default();
+        client_config.protocols(&[b"doq"]);
+        let client_config = client_config.build();
+
+        let mut builder = Endpoint::builder();
+        builder.default_client_config(client_config);
+        let endpoint = futures::future::result(builder.bind(&bind_address).map_err(Error::from)).map(
+            |(driver, endpoint, _incomming)| {
+                tokio::spawn(driver.map_err(|err| error!("Endpoint error {}", err)));
+                endpoint
+            },
+        );
+
+        let connection = upstream
+            .and_then(move |addrs| {
+                addrs
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| format_err!("Could not resolve DoQ upstream {}", upstream_host))
+            })
+            .join(endpoint)
+            .and_then(move |(upstream, endpoint)| {
+                endpoint
+                    .connect(&upstream, &self.upstream_host)
+                    .map_err(Error::from)
+            })
+            .and_then(|connecting| connecting.map_err(Error::from))
+            .map(|(driver, connection, _incomming)| {
+                tokio::spawn(driver.map_err(|err| error!("Connection error {}", err)));
+                connection
+            });
+
+        Box::new(connection.and_then(move |connection| {
+            let aaaa = Self::resolve_one(connection.clone(), hostname, RecordType::AAAA);
+            let a = Self::resolve_one(connection, hostname, RecordType::A);
+
+            aaaa.join(a).and_then(|(aaaa_ips, a_ips)| {
+                let mut ips = aaaa_ips;
+                ips.extend(a_ips);
+
+                if ips.is_empty() {
+                    bail!("No DNS record found");
+                }
+
+                Ok(to_addresses(ips, port))
+            })
+        }))
+    }
+}
+
+/// Which `Resolve` implementation to use, selected with `--resolver`.
+#[derive(Debug)]
+pub enum ResolverKind {
+    System,
+    Doq { host: String, port: u16 },
+}
+
+impl ResolverKind {
+    pub fn build(&self, source: Option<SocketAddr>) -> Box<dyn Resolve> {
+        match self {
+            ResolverKind::System => Box::new(SystemResolver::new(source)),
+            ResolverKind::Doq { host, port } => {
+                Box::new(DoqResolver::new(host.clone(), *port, source))
+            }
+        }
+    }
+}
+
+impl FromStr for ResolverKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        if s == "system" {
+            return Ok(ResolverKind::System);
+        }
+
+        if let Some(rest) = s.strip_prefix("doq://") {
+            // A bracketed host, `[<ipv6>]` or `[<ipv6>]:<port>`, is needed to tell an IPv6
+            // literal's own colons apart from the one separating host and port.
+            let (host, port) = match rest.strip_prefix('[') {
+                Some(rest) => {
+                    let end = rest
+                        .find(']')
+                        .ok_or_else(|| format_err!("Missing closing ']' in resolver {:?}", s))?;
+                    let host = &rest[..end];
+                    let port = match &rest[end + 1..] {
+                        "" => 853,
+                        rest => rest
+                            .strip_prefix(':')
+                            .ok_or_else(|| {
+                                format_err!("Unexpected trailing characters in resolver {:?}", s)
+                            })?
+                            .parse()?,
+                    };
+                    (host.to_string(), port)
+                }
+                None => {
+                    let mut parts = rest.splitn(2, ':');
+                    let host = parts.next().unwrap_or("").to_string();
+                    let port = parts.next().map(str::parse).transpose()?.unwrap_or(853);
+                    (host, port)
+                }
+            };
+
+            if host.is_empty() {
+                bail!("Missing host in resolver {:?}", s);
+            }
+
+            return Ok(ResolverKind::Doq { host, port });
+        }
+
+        bail!(
+            "Unknown resolver {:?}, expected \"system\" or \"doq://host[:port]\"",
+            s
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleave_puts_ipv6_before_ipv4() {
+        let ips = vec![
+            "127.0.0.1".parse().unwrap(),
+            "::1".parse().unwrap(),
+            "127.0.0.2".parse().unwrap(),
+            "::2".parse().unwrap(),
+        ];
+
+        assert_eq!(
+            interleave(ips),
+            vec![
+                "::1".parse().unwrap(),
+                "127.0.0.1".parse().unwrap(),
+                "::2".parse().unwrap(),
+                "127.0.0.2".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolver_kind_parses_doq_with_explicit_port() {
+        let resolver: ResolverKind = "doq://example.com:8530".parse().unwrap();
+
+        match resolver {
+            ResolverKind::Doq { host, port } => {
+                assert_eq!(host, "example.com");
+                assert_eq!(port, 8530);
+            }
+            ResolverKind::System => panic!("expected a Doq resolver"),
+        }
+    }
+
+    #[test]
+    fn resolver_kind_parses_doq_with_default_port() {
+        let resolver: ResolverKind = "doq://example.com".parse().unwrap();
+
+        match resolver {
+            ResolverKind::Doq { host, port } => {
+                assert_eq!(host, "example.com");
+                assert_eq!(port, 853);
+            }
+            ResolverKind::System => panic!("expected a Doq resolver"),
+        }
+    }
+
+    #[test]
+    fn resolver_kind_parses_doq_with_bracketed_ipv6_host_and_port() {
+        let resolver: ResolverKind = "doq://[::1]:8530".parse().unwrap();
+
+        match resolver {
+            ResolverKind::Doq { host, port } => {
+                assert_eq!(host, "::1");
+                assert_eq!(port, 8530);
+            }
+            ResolverKind::System => panic!("expected a Doq resolver"),
+        }
+    }
+
+    #[test]
+    fn resolver_kind_parses_doq_with_bracketed_ipv6_host_and_default_port() {
+        let resolver: ResolverKind = "doq://[::1]".parse().unwrap();
+
+        match resolver {
+            ResolverKind::Doq { host, port } => {
+                assert_eq!(host, "::1");
+                assert_eq!(port, 853);
+            }
+            ResolverKind::System => panic!("expected a Doq resolver"),
+        }
+    }
+}